@@ -16,17 +16,58 @@
 
 extern crate core;
 
-use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use std::fs::File;
 use std::io;
 #[allow(unused_imports)]
 use std::io::BufRead;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
 use std::time::Instant;
-#[allow(unused_imports)]
-use fnv::{FnvHashMap, FnvHashSet};
+use ahash::{AHashMap, AHashSet};
+use clap::{Parser, ValueEnum};
+use memmap2::Mmap;
+use rayon::prelude::*;
+
+// ahash is noticeably faster than fnv for the u64-keyed maps built while
+// parsing and analyzing large heaps.
+type MyHashMap<K, V> = AHashMap<K, V>;
+type MyHashSet<T> = AHashSet<T>;
+
+// Sentinel address of the synthetic super-root node added to the object graph
+// when computing dominators. Real addresses come from the process' address
+// space and won't collide with it in practice.
+const SUPER_ROOT: u64 = u64::MAX;
+
+// Magic header identifying the binary dump format handled by `parse_binary`.
+// The text format's first line is always "objects", which can never collide
+// with this.
+const BINARY_MAGIC: &[u8; 8] = b"PYHEAPB1";
+
+/// Computes per-object and per-thread retained heap sizes for a Python heap dump.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path to the heap dump; reads from stdin if omitted
+    input: Option<PathBuf>,
+
+    /// Only report the N objects with the largest retained heap
+    #[arg(long)]
+    top: Option<usize>,
+
+    /// Only report objects whose retained heap is at least this many bytes
+    #[arg(long)]
+    threshold: Option<u32>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+}
 
-type MyHashMap<K, V> = FnvHashMap<K, V>;
-type MyHashSet<T> = FnvHashSet<T>;
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
 
 #[derive(Debug)]
 struct HeapObject {
@@ -91,12 +132,95 @@ fn parse_address_list(s: String) -> MyHashSet<u64> {
         .collect::<MyHashSet<u64>>()
 }
 
+// Binary dump format (all integers little-endian), read directly out of a
+// memory-mapped byte slice with no per-record allocation beyond the
+// `HeapObject`/thread-locals sets and thread name strings the rest of the
+// program already needs:
+//
+//   magic: [u8; 8]
+//   object_count: u64
+//   objects: object_count times
+//     address: u64, size: u32,
+//     referent_count: u32, referents: [u64; referent_count],
+//     inbound_count: u32, inbound_references: [u64; inbound_count]
+//   thread_count: u64
+//   threads: thread_count times
+//     name_len: u32, name: [u8; name_len] (UTF-8),
+//     local_count: u32, locals: [u64; local_count]
+fn read_u64(data: &[u8], pos: &mut usize) -> u64 {
+    let v = u64::from_le_bytes(data[*pos..*pos + 8].try_into().expect("Broken input"));
+    *pos += 8;
+    v
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> u32 {
+    let v = u32::from_le_bytes(data[*pos..*pos + 4].try_into().expect("Broken input"));
+    *pos += 4;
+    v
+}
+
+fn read_addresses(data: &[u8], pos: &mut usize, count: u32) -> MyHashSet<u64> {
+    (0..count).map(|_| read_u64(data, pos)).collect()
+}
+
+fn parse_binary(data: &[u8]) -> (MyHashMap<u64, HeapObject>, MyHashMap<String, MyHashSet<u64>>) {
+    let mut pos = BINARY_MAGIC.len();
+
+    let object_count = read_u64(data, &mut pos);
+    let mut objects: MyHashMap<u64, HeapObject> = MyHashMap::default();
+    for _ in 0..object_count {
+        let address = read_u64(data, &mut pos);
+        let size = read_u32(data, &mut pos);
+        let referent_count = read_u32(data, &mut pos);
+        let referents = read_addresses(data, &mut pos, referent_count);
+        let inbound_count = read_u32(data, &mut pos);
+        let inbound_references = read_addresses(data, &mut pos, inbound_count);
+        objects.insert(address, HeapObject { size, referents, inbound_references });
+    }
+
+    let thread_count = read_u64(data, &mut pos);
+    let mut threads: MyHashMap<String, MyHashSet<u64>> = MyHashMap::default();
+    for _ in 0..thread_count {
+        let name_len = read_u32(data, &mut pos) as usize;
+        let name = std::str::from_utf8(&data[pos..pos + name_len])
+            .expect("Broken input")
+            .to_string();
+        pos += name_len;
+        let local_count = read_u32(data, &mut pos);
+        let locals = read_addresses(data, &mut pos, local_count);
+        threads.insert(name, locals);
+    }
+
+    (objects, threads)
+}
+
+// Opens `path`, sniffs the binary dump's magic header and either memory-maps
+// the file and parses it in place, or falls back to the line-oriented text
+// format read through a buffered reader as before.
+fn read_from_file(path: &PathBuf) -> (MyHashMap<u64, HeapObject>, MyHashMap<String, MyHashSet<u64>>) {
+    let mut file = File::open(path).expect("Cannot open input file");
+
+    let mut magic_buf = [0u8; BINARY_MAGIC.len()];
+    let is_binary = file.read_exact(&mut magic_buf).is_ok() && magic_buf == *BINARY_MAGIC;
+    file.rewind().expect("Cannot seek input file");
+
+    if is_binary {
+        let mmap = unsafe { Mmap::map(&file).expect("Cannot mmap input file") };
+        parse_binary(&mmap)
+    } else {
+        let reader = io::BufReader::new(file);
+        parse(reader.lines().map(|r| r.expect("Broken input")))
+    }
+}
+
 struct RetainedHeapCalculator<> {
     objects: MyHashMap<u64, HeapObject>,
     threads: MyHashMap<String, MyHashSet<u64>>,
     object_retained_heap: MyHashMap<u64, u32>,
     thread_retained_heap: MyHashMap<String, u32>,
-    subtree_roots: MyHashSet<u64>
+    // Maps every object address to the representative address of its
+    // strongly-connected component (a component of size one maps to itself).
+    component_of: MyHashMap<u64, u64>,
 }
 
 impl RetainedHeapCalculator {
@@ -106,241 +230,607 @@ impl RetainedHeapCalculator {
             threads,
             object_retained_heap: MyHashMap::default(),
             thread_retained_heap: MyHashMap::default(),
-            subtree_roots: MyHashSet::default()
+            component_of: MyHashMap::default(),
         }
     }
 
     pub fn calculate(&mut self) {
-        self.find_strict_subtrees();
-        self.calculate_for_all_objects();
-        self.calculate_for_all_threads()
-    }
-
-    fn find_strict_subtrees(&mut self) {
-        let mut front: MyHashSet<u64> = MyHashSet::default();
-        for (addr, obj) in self.objects.borrow() {
-            if obj.referents.is_empty() && obj.inbound_references.len() < 2 {
-                self.subtree_roots.insert(*addr);
-                self.object_retained_heap.insert(*addr, obj.size);
-                front.extend(obj.inbound_references.iter())
-            }
-        }
+        self.component_of = Self::find_components(&self.objects);
+        let (condensed_objects, condensed_threads) =
+            Self::condense(&self.objects, &self.threads, &self.component_of);
+
+        let condensed_retained_heap =
+            Self::calculate_for_all_objects(&condensed_objects, &condensed_threads);
+        // Every member of a cycle is exactly as "alive" as the cycle itself,
+        // so it gets the whole component's retained heap, not a fraction of it.
+        self.object_retained_heap = self.objects.keys()
+            .map(|addr| (*addr, condensed_retained_heap[&self.component_of[addr]]))
+            .collect();
+
+        self.thread_retained_heap = Self::calculate_for_all_threads(&condensed_objects, &condensed_threads);
+    }
 
-        let mut next_front: MyHashSet<u64> = MyHashSet::default();
-        while &next_front != &front {
-            for current_addr in front.iter() {
-                let obj = self.objects.get(&current_addr).unwrap();
-                // Skip if it has more than one inbound references.
-                if obj.inbound_references.len() > 1 {
-                    continue;
-                }
-                // Consider later if it has children not yet roots.
-                if (&obj.referents - &(self.subtree_roots)).len() > 0 {
-                    next_front.insert(*current_addr);
-                    continue;
-                }
+    // Maps every object address to the representative address of its
+    // strongly-connected component, so callers can tell which objects were
+    // condensed together into a reference cycle (a component of size one
+    // maps to itself).
+    pub fn component_of(&self) -> &MyHashMap<u64, u64> {
+        &self.component_of
+    }
 
-                self.subtree_roots.insert(*current_addr);
-                let ret_heap = obj.size + obj.referents.iter()
-                    .map(|r| self.object_retained_heap.get(r).unwrap())
-                    .sum::<u32>();
-                self.object_retained_heap.insert(*current_addr, ret_heap);
-                next_front.extend(obj.inbound_references.iter())
-            }
+    // Collapses every strongly-connected component of `objects` into a single
+    // super-node (summed size, union of inter-component edges) so the
+    // dominator-tree computation below never has to reason about cycles.
+    fn condense(
+        objects: &MyHashMap<u64, HeapObject>,
+        threads: &MyHashMap<String, MyHashSet<u64>>,
+        component_of: &MyHashMap<u64, u64>,
+    ) -> (MyHashMap<u64, HeapObject>, MyHashMap<String, MyHashSet<u64>>) {
+        let mut condensed: MyHashMap<u64, HeapObject> = MyHashMap::default();
+        for (&addr, obj) in objects {
+            let rep = component_of[&addr];
+            let entry = condensed.entry(rep).or_insert_with(|| HeapObject {
+                size: 0,
+                referents: MyHashSet::default(),
+                inbound_references: MyHashSet::default(),
+            });
+            entry.size += obj.size;
+            entry.referents.extend(obj.referents.iter().map(|r| component_of[r]));
+            entry.inbound_references.extend(obj.inbound_references.iter().map(|r| component_of[r]));
+        }
+        // Drop self-loops created by condensing intra-component edges.
+        for (&rep, obj) in condensed.iter_mut() {
+            obj.referents.remove(&rep);
+            obj.inbound_references.remove(&rep);
+        }
 
-            if front == next_front {
-                break;
-            }
+        let condensed_threads = threads.iter()
+            .map(|(name, locals)| (name.clone(), locals.iter().map(|l| component_of[l]).collect()))
+            .collect();
 
-            front.clear();
-            front.extend(&next_front);
-            next_front.clear();
-        }
+        (condensed, condensed_threads)
     }
 
-    fn calculate_for_all_objects(&mut self) {
-        let addrs = self.objects.keys()
-            .cloned().collect::<Vec<u64>>();  // make borrow checker happy
-        for addr in addrs {
-            let mut inbound_reference_view: MyHashMap<u64, i32> = MyHashMap::default();
-            // Imitate deletion of the initial address.
-            inbound_reference_view.insert(addr, 0);
-            let mut front = vec![addr];
-            let ret_heap = self.retained_heap0(
-                &mut inbound_reference_view,
-                &mut front,
-                true
-            );
-            self.object_retained_heap.insert(addr, ret_heap);
+    // Finds strongly-connected components of the `referents` graph with
+    // Tarjan's algorithm (single DFS tracking per-node index/lowlink, an
+    // explicit node stack and on-stack set; a component is popped whenever a
+    // node's lowlink equals its own index). The DFS itself is run iteratively
+    // with an explicit call stack to avoid overflowing on deep heaps.
+    fn find_components(objects: &MyHashMap<u64, HeapObject>) -> MyHashMap<u64, u64> {
+        struct CallFrame {
+            node: u64,
+            remaining_referents: Vec<u64>,
         }
-    }
 
-    fn calculate_for_all_threads(&mut self) {
-        for (thread, locals) in self.threads.clone() {
-            let mut inbound_reference_view: MyHashMap<u64, i32> = MyHashMap::default();
-            for obj in locals.iter() {
-                let view = self.objects.get(obj).unwrap().inbound_references.len() as i32;
-                inbound_reference_view.insert(*obj, view);
+        let mut index_counter: u32 = 0;
+        let mut index: MyHashMap<u64, u32> = MyHashMap::default();
+        let mut lowlink: MyHashMap<u64, u32> = MyHashMap::default();
+        let mut on_stack: MyHashSet<u64> = MyHashSet::default();
+        let mut node_stack: Vec<u64> = Vec::new();
+        let mut component_of: MyHashMap<u64, u64> = MyHashMap::default();
 
-                for (other_thread, other_locals) in self.threads.iter() {
-                    if *other_thread == *thread {
-                        continue
+        for &start in objects.keys() {
+            if index.contains_key(&start) {
+                continue;
+            }
+
+            index.insert(start, index_counter);
+            lowlink.insert(start, index_counter);
+            index_counter += 1;
+            node_stack.push(start);
+            on_stack.insert(start);
+            let mut call_stack = vec![CallFrame {
+                node: start,
+                remaining_referents: objects[&start].referents.iter().cloned().collect(),
+            }];
+
+            while let Some(frame) = call_stack.last_mut() {
+                let v = frame.node;
+                match frame.remaining_referents.pop() {
+                    Some(w) => {
+                        if !index.contains_key(&w) {
+                            index.insert(w, index_counter);
+                            lowlink.insert(w, index_counter);
+                            index_counter += 1;
+                            node_stack.push(w);
+                            on_stack.insert(w);
+                            call_stack.push(CallFrame {
+                                node: w,
+                                remaining_referents: objects.get(&w)
+                                    .map(|obj| obj.referents.iter().cloned().collect())
+                                    .unwrap_or_default(),
+                            });
+                        } else if on_stack.contains(&w) {
+                            let l = lowlink[&v].min(index[&w]);
+                            lowlink.insert(v, l);
+                        }
                     }
-                    if other_locals.contains(obj) {
-                        *inbound_reference_view.get_mut(obj).unwrap() += 1;
+                    None => {
+                        call_stack.pop();
+                        if lowlink[&v] == index[&v] {
+                            let mut members = Vec::new();
+                            loop {
+                                let w = node_stack.pop().unwrap();
+                                on_stack.remove(&w);
+                                members.push(w);
+                                if w == v {
+                                    break;
+                                }
+                            }
+                            let rep = *members.iter().min().unwrap();
+                            for m in members {
+                                component_of.insert(m, rep);
+                            }
+                        }
+                        if let Some(parent) = call_stack.last() {
+                            let l = lowlink[&parent.node].min(lowlink[&v]);
+                            lowlink.insert(parent.node, l);
+                        }
                     }
                 }
             }
-
-            let mut front = locals.iter().cloned().collect::<Vec<u64>>();
-            let ret_heap = self.retained_heap0(
-                &mut inbound_reference_view,
-                &mut front,
-                false,
-            );
-            self.thread_retained_heap.insert(thread, ret_heap);
         }
+
+        component_of
     }
 
-    fn retained_heap0(&mut self,
-                      inbound_reference_view: &mut MyHashMap<u64, i32>,
-                      front: &mut Vec<u64>,
-                      use_subtrees: bool
-    ) -> u32 {
-        let mut result: u32 = 0;
-        let mut deleted: MyHashSet<u64> = MyHashSet::default();
-
-        loop {
-            front.sort_by_key(|x| inbound_reference_view.get(x).unwrap());
-            front.reverse();
-
-            let (retained, deletion_happened) = self.retained_heap_calculation_iteration(
-                front, inbound_reference_view, &mut deleted, use_subtrees
-            );
-            if !deletion_happened {
-                assert_eq!(retained, 0);
-                break;
-            }
-            result += retained;
+    // Retained heap of an object is the size of its subtree in the dominator
+    // tree rooted at a synthetic super-root connected to every GC root (here,
+    // every thread local). This correctly handles objects shared between
+    // several parents (diamonds), unlike summing along a single path.
+    fn calculate_for_all_objects(
+        objects: &MyHashMap<u64, HeapObject>,
+        threads: &MyHashMap<String, MyHashSet<u64>>,
+    ) -> MyHashMap<u64, u32> {
+        let roots: MyHashSet<u64> = threads.values().flatten().cloned().collect();
+
+        let mut synthetic_edges: MyHashMap<u64, MyHashSet<u64>> = MyHashMap::default();
+        synthetic_edges.insert(SUPER_ROOT, roots);
+
+        let mut retained = Self::compute_retained_heap(objects, &synthetic_edges);
+        // Objects unreachable from any thread don't appear in the dominator
+        // tree at all; fall back to their own size for those.
+        for (addr, obj) in objects {
+            retained.entry(*addr).or_insert(obj.size);
         }
-        result
+        retained
     }
 
-    fn retained_heap_calculation_iteration(&mut self,
-                                           front: &mut Vec<u64>,
-                                           inbound_reference_view: &mut MyHashMap<u64, i32>,
-                                           deleted: &mut MyHashSet<u64>,
-                                           use_subtrees: bool) -> (u32, bool) {
-        let mut retained: u32 = 0;
-        let mut deletion_happened = false;
+    // A thread's retained heap is what it alone keeps alive: the dominator
+    // subtree rooted at a synthetic node reachable only via that thread's
+    // locals, with every other thread's locals wired up as alternative roots.
+    //
+    // Threads (typically tens) are additionally analyzed concurrently with
+    // each other here; each one's `compute_retained_heap` call also
+    // parallelizes internally over the object graph (see the dominator
+    // fixpoint loop in `compute_retained_heap`).
+    fn calculate_for_all_threads(
+        objects: &MyHashMap<u64, HeapObject>,
+        threads: &MyHashMap<String, MyHashSet<u64>>,
+    ) -> MyHashMap<String, u32> {
+        let thread_names: Vec<String> = threads.keys().cloned().collect();
+        let thread_node_ids: MyHashMap<String, u64> = thread_names.iter().enumerate()
+            .map(|(i, name)| (name.clone(), SUPER_ROOT - 1 - i as u64))
+            .collect();
+
+        // Each thread's dominator-tree run only reads the shared `objects`/
+        // `threads`/`thread_node_ids` state and produces its own entry, so
+        // the threads can be analyzed concurrently with no shared mutable
+        // state to synchronize.
+        let results: Vec<(String, u32)> = thread_names.par_iter()
+            .map(|thread| {
+                let thread_node = thread_node_ids[thread];
+
+                let mut super_root_targets: MyHashSet<u64> = MyHashSet::default();
+                super_root_targets.insert(thread_node);
+                for other_thread in &thread_names {
+                    if other_thread != thread {
+                        super_root_targets.extend(threads[other_thread].iter().cloned());
+                    }
+                }
+
+                let mut synthetic_edges: MyHashMap<u64, MyHashSet<u64>> = MyHashMap::default();
+                synthetic_edges.insert(SUPER_ROOT, super_root_targets);
+                synthetic_edges.insert(thread_node, threads[thread].clone());
 
-        for i in (0..front.len()).rev() {
-            let current = front[i];
+                let retained = Self::compute_retained_heap(objects, &synthetic_edges);
+                let ret_heap = retained.get(&thread_node).cloned().unwrap_or(0);
+                (thread.clone(), ret_heap)
+            })
+            .collect();
+        results.into_iter().collect()
+    }
 
-            if *inbound_reference_view.get(&current).unwrap() > 0 {
-                break;
+    // Computes immediate dominators of every node reachable from SUPER_ROOT
+    // (via `synthetic_edges`, which may introduce extra virtual nodes and
+    // edges on top of the real object graph) using the Cooper-Harvey-Kennedy
+    // iterative algorithm, then accumulates retained heap bottom-up over the
+    // resulting dominator tree.
+    fn compute_retained_heap(
+        objects: &MyHashMap<u64, HeapObject>,
+        synthetic_edges: &MyHashMap<u64, MyHashSet<u64>>,
+    ) -> MyHashMap<u64, u32> {
+        let mut synthetic_preds: MyHashMap<u64, Vec<u64>> = MyHashMap::default();
+        for (src, targets) in synthetic_edges {
+            for t in targets {
+                synthetic_preds.entry(*t).or_default().push(*src);
             }
-            if deleted.contains(&current) {
-                continue;
+        }
+
+        let successors = |node: u64| -> Vec<u64> {
+            match synthetic_edges.get(&node) {
+                Some(targets) => targets.iter().cloned().collect(),
+                None => objects.get(&node)
+                    .map(|obj| obj.referents.iter().cloned().collect())
+                    .unwrap_or_default(),
+            }
+        };
+        let predecessors = |node: u64| -> Vec<u64> {
+            let mut preds = synthetic_preds.get(&node).cloned().unwrap_or_default();
+            if let Some(obj) = objects.get(&node) {
+                preds.extend(obj.inbound_references.iter().cloned());
             }
+            preds
+        };
 
-            front.remove(i);
-            deleted.insert(current);
-            deletion_happened = true;
-
-            if use_subtrees && self.subtree_roots.contains(&current) {
-                retained += self.object_retained_heap.get(&current).unwrap();
-            } else if self.objects.contains_key(&current) {
-                let obj = self.objects.get(&current).unwrap();
-                retained += obj.size;
-                let to_be_added_to_front = &(obj.referents) - deleted;
-                self.update_inbound_references_view(&to_be_added_to_front, inbound_reference_view);
-                front.extend(to_be_added_to_front);
+        let postorder = Self::depth_first_postorder(SUPER_ROOT, &successors);
+        let order_num: MyHashMap<u64, u32> = postorder.iter().enumerate()
+            .map(|(i, addr)| (*addr, i as u32))
+            .collect();
+
+        // Reverse postorder, excluding the root itself (its idom is fixed).
+        let rpo: Vec<u64> = postorder.iter().rev().skip(1).cloned().collect();
+
+        let mut idom: MyHashMap<u64, u64> = MyHashMap::default();
+        idom.insert(SUPER_ROOT, SUPER_ROOT);
+
+        // Each round recomputes every node's candidate immediate dominator
+        // from the *previous* round's snapshot of `idom`, so the whole `rpo`
+        // — one entry per object, the part of this computation that actually
+        // scales with heap size — can be processed with rayon instead of one
+        // node at a time. The single-threaded version folds a round's own
+        // updates into itself as it goes and so usually converges in 2-3
+        // passes; reading a stale snapshot here can take a few more passes
+        // to reach the same fixed point, trading iteration count for
+        // cross-core parallelism on graphs large enough for that to matter.
+        let mut changed = true;
+        while changed {
+            // A node may have no predecessor in `idom` yet simply because
+            // none of its predecessors have been reached by an earlier round
+            // (information spreads outward from the root one round at a
+            // time here, unlike the in-order single-threaded version); skip
+            // it this round rather than treating that as a broken graph.
+            let updates: Vec<(u64, u64)> = rpo.par_iter()
+                .filter_map(|&node| {
+                    let mut new_idom: Option<u64> = None;
+                    for p in predecessors(node) {
+                        if !idom.contains_key(&p) {
+                            continue;
+                        }
+                        new_idom = Some(match new_idom {
+                            None => p,
+                            Some(cur) => Self::intersect(&idom, &order_num, p, cur),
+                        });
+                    }
+                    new_idom.map(|new_idom| (node, new_idom))
+                })
+                .collect();
+
+            changed = false;
+            for (node, new_idom) in updates {
+                if idom.get(&node) != Some(&new_idom) {
+                    idom.insert(node, new_idom);
+                    changed = true;
+                }
             }
         }
 
-        (retained, deletion_happened)
+        let mut dominator_children: MyHashMap<u64, Vec<u64>> = MyHashMap::default();
+        for (&node, &dom) in &idom {
+            if node != dom {
+                dominator_children.entry(dom).or_default().push(node);
+            }
+        }
+
+        let mut retained: MyHashMap<u64, u32> = MyHashMap::default();
+        for &node in &postorder {
+            if node == SUPER_ROOT {
+                continue;
+            }
+            let own_size = objects.get(&node).map(|obj| obj.size).unwrap_or(0);
+            let children_sum: u32 = dominator_children.get(&node)
+                .map(|children| children.iter().map(|c| retained[c]).sum())
+                .unwrap_or(0);
+            retained.insert(node, own_size + children_sum);
+        }
+
+        retained
     }
 
-    fn update_inbound_references_view(&mut self,
-                                      to_be_added_to_front: &MyHashSet<u64>,
-                                      inbound_reference_view: &mut MyHashMap<u64, i32>) {
-        for r in to_be_added_to_front {
-            match inbound_reference_view.get_mut(r) {
-                Some(v) => *v -= 1,
-                None => {
-                    match self.objects.get(r) {
-                        Some(obj) => {
-                            let view = (obj.inbound_references.len() - 1) as i32;
-                            inbound_reference_view.insert(*r, view);
-                        }
-                        None => {
-                            inbound_reference_view.insert(*r, 0);
+    // Iterative (stack-based, to avoid blowing the call stack on deep heaps)
+    // DFS postorder traversal of the graph reachable from `root`.
+    fn depth_first_postorder<F>(root: u64, successors: &F) -> Vec<u64>
+        where F: Fn(u64) -> Vec<u64>
+    {
+        enum Frame { Enter(u64), Leave(u64) }
+
+        let mut visited: MyHashSet<u64> = MyHashSet::default();
+        let mut postorder: Vec<u64> = Vec::new();
+        let mut stack = vec![Frame::Enter(root)];
+        visited.insert(root);
+
+        while let Some(frame) = stack.pop() {
+            match frame {
+                Frame::Enter(node) => {
+                    stack.push(Frame::Leave(node));
+                    for child in successors(node) {
+                        if visited.insert(child) {
+                            stack.push(Frame::Enter(child));
                         }
                     }
                 }
+                Frame::Leave(node) => postorder.push(node),
             }
         }
+
+        postorder
+    }
+
+    // Walks the two fingers up the dominator tree, always advancing the one
+    // with the smaller postorder number, until they meet at the common
+    // dominator.
+    fn intersect(idom: &MyHashMap<u64, u64>, order_num: &MyHashMap<u64, u32>, mut a: u64, mut b: u64) -> u64 {
+        while a != b {
+            while order_num[&a] < order_num[&b] {
+                a = idom[&a];
+            }
+            while order_num[&b] < order_num[&a] {
+                b = idom[&b];
+            }
+        }
+        a
     }
 }
 
 fn main() {
-//     let input_str =
-// "objects
-// 1
-// 10
-// 3
-// 2
-// 2
-// 20
-// 1 6
-// 3
-// 3
-// 30
-// 2 4
-// 1 5
-// 4
-// 40
-// 5
-// 3
-// 5
-// 50
-// 3
-// 4
-// 6
-// 60
-// 7
-// 2
-// 7
-// 70
-//
-// 6
-// threads
-// thread1
-// 1 2
-// thread2
-// 5 7";
+    let cli = Cli::parse();
 
     let start = Instant::now();
-    let input = io::stdin().lines()
-        .map(|r| r.expect("Broken input"));
-    // let input = io::Cursor::new(input_str).lines()
-    //     .map(|r| r.expect("Broken input"));
+    let (objects, threads) = match &cli.input {
+        Some(path) => read_from_file(path),
+        None => {
+            let lines = io::stdin().lines().map(|r| r.expect("Broken input"));
+            parse(lines)
+        }
+    };
     let duration = start.elapsed();
-
-    let (objects, threads) = parse(input);
-    // println!("Objects: {:?}", objects);
     eprintln!("Input read and parsed in {} s", duration.as_secs());
 
     let mut retained_heap_calculator = RetainedHeapCalculator::new(objects, threads);
     retained_heap_calculator.calculate();
 
+    let component_of = retained_heap_calculator.component_of().clone();
+    let mut objects_report: Vec<(u64, u32, u64)> = retained_heap_calculator.object_retained_heap
+        .into_iter()
+        .map(|(addr, ret_heap)| (addr, ret_heap, component_of[&addr]))
+        .collect();
+    objects_report.sort_by_key(|(_, ret_heap, _)| std::cmp::Reverse(*ret_heap));
+    if let Some(threshold) = cli.threshold {
+        objects_report.retain(|(_, ret_heap, _)| *ret_heap >= threshold);
+    }
+    if let Some(top) = cli.top {
+        objects_report.truncate(top);
+    }
+
+    let mut threads_report: Vec<(String, u32)> =
+        retained_heap_calculator.thread_retained_heap.into_iter().collect();
+    threads_report.sort_by_key(|(_, ret_heap)| std::cmp::Reverse(*ret_heap));
+
+    match cli.output {
+        OutputFormat::Text => print_text(&objects_report, &threads_report),
+        OutputFormat::Json => print_json(&objects_report, &threads_report),
+    }
+}
+
+fn print_text(objects_report: &[(u64, u32, u64)], threads_report: &[(String, u32)]) {
     println!("objects");
-    for (addr, ret_heap) in retained_heap_calculator.object_retained_heap {
-        println!("{} {}", addr, ret_heap)
+    for (addr, ret_heap, component) in objects_report {
+        println!("{} {} {}", addr, ret_heap, component)
     }
     println!("threads");
-    for (thread, ret_heap) in retained_heap_calculator.thread_retained_heap {
+    for (thread, ret_heap) in threads_report {
         println!("{} {}", thread, ret_heap)
     }
 }
+
+fn print_json(objects_report: &[(u64, u32, u64)], threads_report: &[(String, u32)]) {
+    println!("{{");
+    println!("  \"objects\": [");
+    for (i, (addr, ret_heap, component)) in objects_report.iter().enumerate() {
+        let comma = if i + 1 < objects_report.len() { "," } else { "" };
+        println!(
+            "    {{\"address\": {}, \"retained_heap\": {}, \"component\": {}}}{}",
+            addr, ret_heap, component, comma
+        );
+    }
+    println!("  ],");
+    println!("  \"threads\": [");
+    for (i, (name, ret_heap)) in threads_report.iter().enumerate() {
+        let comma = if i + 1 < threads_report.len() { "," } else { "" };
+        println!(
+            "    {{\"name\": {}, \"retained_heap\": {}}}{}",
+            json_escape(name), ret_heap, comma
+        );
+    }
+    println!("  ]");
+    println!("}}");
+}
+
+// `{:?}` (Rust's Debug) is not JSON: it escapes non-ASCII/control bytes as
+// `\u{..}`-style sequences, which no JSON parser accepts. Thread names come
+// straight off the process under inspection, so escape them properly.
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(size: u32, referents: &[u64], inbound_references: &[u64]) -> HeapObject {
+        HeapObject {
+            size,
+            referents: referents.iter().cloned().collect(),
+            inbound_references: inbound_references.iter().cloned().collect(),
+        }
+    }
+
+    fn calculate(
+        objects: MyHashMap<u64, HeapObject>,
+        threads: MyHashMap<String, MyHashSet<u64>>,
+    ) -> RetainedHeapCalculator {
+        let mut calculator = RetainedHeapCalculator::new(objects, threads);
+        calculator.calculate();
+        calculator
+    }
+
+    #[test]
+    fn diamond_is_retained_once_by_the_shared_ancestor() {
+        // 1 -> 2, 3; 2 -> 4; 3 -> 4. Only 1 is a thread local, so 1 must
+        // dominate everything below it and retain the whole subtree rather
+        // than 4 being double-counted under both 2 and 3.
+        let mut objects: MyHashMap<u64, HeapObject> = MyHashMap::default();
+        objects.insert(1, object(1, &[2, 3], &[]));
+        objects.insert(2, object(1, &[4], &[1]));
+        objects.insert(3, object(1, &[4], &[1]));
+        objects.insert(4, object(10, &[], &[2, 3]));
+
+        let mut threads: MyHashMap<String, MyHashSet<u64>> = MyHashMap::default();
+        threads.insert("main".to_string(), [1].into_iter().collect());
+
+        let calculator = calculate(objects, threads);
+
+        assert_eq!(calculator.object_retained_heap[&1], 13);
+        assert_eq!(calculator.object_retained_heap[&2], 1);
+        assert_eq!(calculator.object_retained_heap[&3], 1);
+        assert_eq!(calculator.object_retained_heap[&4], 10);
+    }
+
+    #[test]
+    fn linear_chain_retains_everything_below_it() {
+        // 1 -> 2 -> 3, each uniquely dominating the next.
+        let mut objects: MyHashMap<u64, HeapObject> = MyHashMap::default();
+        objects.insert(1, object(1, &[2], &[]));
+        objects.insert(2, object(2, &[3], &[1]));
+        objects.insert(3, object(3, &[], &[2]));
+
+        let mut threads: MyHashMap<String, MyHashSet<u64>> = MyHashMap::default();
+        threads.insert("main".to_string(), [1].into_iter().collect());
+
+        let calculator = calculate(objects, threads);
+
+        assert_eq!(calculator.object_retained_heap[&1], 6);
+        assert_eq!(calculator.object_retained_heap[&2], 5);
+        assert_eq!(calculator.object_retained_heap[&3], 3);
+    }
+
+    #[test]
+    fn two_cycle_members_share_the_components_retained_heap() {
+        // 1 <-> 2 form a 2-cycle reachable only through 1, the thread root.
+        // Both members are exactly as "alive" as the component, so both get
+        // its full retained heap rather than half each.
+        let mut objects: MyHashMap<u64, HeapObject> = MyHashMap::default();
+        objects.insert(1, object(2, &[2], &[2]));
+        objects.insert(2, object(3, &[1], &[1]));
+
+        let mut threads: MyHashMap<String, MyHashSet<u64>> = MyHashMap::default();
+        threads.insert("main".to_string(), [1].into_iter().collect());
+
+        let calculator = calculate(objects, threads);
+
+        assert_eq!(calculator.object_retained_heap[&1], 5);
+        assert_eq!(calculator.object_retained_heap[&2], 5);
+    }
+
+    #[test]
+    fn component_of_groups_cycle_members_under_one_representative() {
+        let mut objects: MyHashMap<u64, HeapObject> = MyHashMap::default();
+        objects.insert(1, object(2, &[2], &[2]));
+        objects.insert(2, object(3, &[1], &[1]));
+
+        let mut threads: MyHashMap<String, MyHashSet<u64>> = MyHashMap::default();
+        threads.insert("main".to_string(), [1].into_iter().collect());
+
+        let calculator = calculate(objects, threads);
+
+        assert_eq!(calculator.component_of()[&1], calculator.component_of()[&2]);
+    }
+
+    #[test]
+    fn json_escape_produces_parseable_control_characters() {
+        assert_eq!(json_escape("bad\u{1}name"), "\"bad\\u0001name\"");
+        assert_eq!(json_escape("quote\"back\\slash"), "\"quote\\\"back\\\\slash\"");
+    }
+
+    fn push_u64(buf: &mut Vec<u8>, v: u64) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    #[test]
+    fn parse_binary_round_trips_objects_and_threads() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(BINARY_MAGIC);
+
+        push_u64(&mut buf, 2); // object_count
+        // object 1: size 10, referents [2], inbound []
+        push_u64(&mut buf, 1);
+        push_u32(&mut buf, 10);
+        push_u32(&mut buf, 1);
+        push_u64(&mut buf, 2);
+        push_u32(&mut buf, 0);
+        // object 2: size 20, referents [], inbound [1]
+        push_u64(&mut buf, 2);
+        push_u32(&mut buf, 20);
+        push_u32(&mut buf, 0);
+        push_u32(&mut buf, 1);
+        push_u64(&mut buf, 1);
+
+        push_u64(&mut buf, 1); // thread_count
+        let name = b"main";
+        push_u32(&mut buf, name.len() as u32);
+        buf.extend_from_slice(name);
+        push_u32(&mut buf, 1);
+        push_u64(&mut buf, 1);
+
+        let (objects, threads) = parse_binary(&buf);
+
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[&1].size, 10);
+        assert_eq!(objects[&1].referents, [2].into_iter().collect());
+        assert!(objects[&1].inbound_references.is_empty());
+        assert_eq!(objects[&2].size, 20);
+        assert!(objects[&2].referents.is_empty());
+        assert_eq!(objects[&2].inbound_references, [1].into_iter().collect());
+
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads["main"], [1].into_iter().collect());
+    }
+}